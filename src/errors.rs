@@ -0,0 +1,77 @@
+// Typed errors for Teanga DB
+// -----------------------------------------------------------------------------
+use pyo3::prelude::*;
+use pyo3::exceptions::PyException;
+use pyo3::create_exception;
+
+/// Domain error type for Teanga DB operations.
+///
+/// Variants map to Python exception classes registered on the `teangadb`
+/// module (see `register`), rather than to a message string, so callers can
+/// `except teangadb.DocumentHashError` instead of parsing text.
+/// `LayerExists` and `LayerConflict` raise `LayerExistsError`,
+/// `UnknownLayerType` raises `LayerTypeError` and `DocumentHashMismatch`
+/// raises `DocumentHashError`; every other variant raises the base
+/// `TeangaError` class with the variant's `Display` message.
+#[derive(Debug)]
+pub(crate) enum TeangaError {
+    LayerExists(String),
+    LayerConflict(String),
+    UnknownLayerType(String),
+    UnknownLayer(String),
+    NotAVectorLayer(String),
+    DocumentHashMismatch { expected: String, got: String },
+    DocumentNotFound(String),
+    InvalidLayerValue(String),
+    VectorDimMismatch { expected: usize, got: usize },
+    Serialization(String)
+}
+
+impl std::fmt::Display for TeangaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TeangaError::LayerExists(name) => write!(f, "Layer {} already exists", name),
+            TeangaError::LayerConflict(name) => write!(f, "Layer {} exists with an incompatible description", name),
+            TeangaError::UnknownLayerType(name) => write!(f, "Unknown layer type {}", name),
+            TeangaError::UnknownLayer(name) => write!(f, "Layer {} does not exist in corpus meta", name),
+            TeangaError::NotAVectorLayer(name) => write!(f, "Layer {} is not a vector layer", name),
+            TeangaError::DocumentHashMismatch { expected, got } =>
+                write!(f, "Document fails hash check: {} != {}", expected, got),
+            TeangaError::DocumentNotFound(id) => write!(f, "No such document: {}", id),
+            TeangaError::InvalidLayerValue(msg) => write!(f, "{}", msg),
+            TeangaError::VectorDimMismatch { expected, got } =>
+                write!(f, "Vector has dimension {}, expected {} for this layer", got, expected),
+            TeangaError::Serialization(msg) => write!(f, "{}", msg)
+        }
+    }
+}
+
+impl std::error::Error for TeangaError {}
+
+create_exception!(teangadb, BaseTeangaError, PyException);
+create_exception!(teangadb, LayerExistsError, BaseTeangaError);
+create_exception!(teangadb, LayerTypeError, BaseTeangaError);
+create_exception!(teangadb, DocumentHashError, BaseTeangaError);
+
+impl From<TeangaError> for PyErr {
+    fn from(e: TeangaError) -> PyErr {
+        match e {
+            TeangaError::LayerExists(name) => LayerExistsError::new_err(name),
+            TeangaError::LayerConflict(name) =>
+                LayerExistsError::new_err(format!("Layer {} exists with an incompatible description", name)),
+            TeangaError::UnknownLayerType(name) => LayerTypeError::new_err(name),
+            TeangaError::DocumentHashMismatch { expected, got } =>
+                DocumentHashError::new_err(format!("{} != {}", expected, got)),
+            other => BaseTeangaError::new_err(other.to_string())
+        }
+    }
+}
+
+/// Register the `TeangaError` hierarchy on the `teangadb` module
+pub(crate) fn register(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add("TeangaError", py.get_type::<BaseTeangaError>())?;
+    m.add("LayerExistsError", py.get_type::<LayerExistsError>())?;
+    m.add("LayerTypeError", py.get_type::<LayerTypeError>())?;
+    m.add("DocumentHashError", py.get_type::<DocumentHashError>())?;
+    Ok(())
+}
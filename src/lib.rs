@@ -2,28 +2,51 @@
 // Author: John P. McCrae
 // License: Apache 2.0
 use pyo3::prelude::*;
-use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+mod errors;
+mod serialization;
+
+use errors::TeangaError;
 
 #[pyclass]
 #[derive(Debug,Clone)]
 /// A corpus object
+///
+/// Document payloads are not held in memory: `docs` is a content-addressable
+/// store on disk (keyed by the same hash `add_doc` returns), so `meta` and
+/// `order` stay cheap to clone while a corpus with millions of documents
+/// need not be fully resident.
 struct Corpus {
     #[pyo3(get)]
     meta: HashMap<String, LayerDesc>,
     #[pyo3(get)]
     order: Vec<String>,
+    docs: sled::Db,
     path: String
 }
 
 #[pyclass]
-#[derive(Debug,Clone)]
+#[derive(Debug,Clone,Serialize,Deserialize)]
 /// A layer description
+///
+/// `_meta` keys a `LayerDesc` by its layer name, so `name` is not part of
+/// the on-disk map entry: it is skipped on write and restored from the map
+/// key after reading (see `TeangaVisitor::visit_map`). `type_` is spelled
+/// `type` in the canonical layout, since `type` is a Rust keyword.
 struct LayerDesc {
     #[pyo3(get)]
+    #[serde(default, skip_serializing)]
     name: String,
     #[pyo3(get)]
+    #[serde(rename = "type")]
     type_: LayerType,
     #[pyo3(get)]
+    #[serde(default)]
     on: String,
     #[pyo3(get)]
     data: Option<String>,
@@ -32,19 +55,25 @@ struct LayerDesc {
     #[pyo3(get)]
     target: Option<String>,
     #[pyo3(get)]
-    default: Option<Vec<String>>
+    default: Option<Vec<String>>,
+    #[pyo3(get)]
+    /// The fixed length of vectors stored in this layer, for `type_ == vector`
+    dim: Option<usize>
 }
 
 #[pymethods]
 impl Corpus {
     #[new]
-    /// Create a new corpus
-    fn new(path : String) -> Corpus {
-        Corpus {
+    /// Create a new corpus, opening (or creating) its document store on disk
+    /// at `path`
+    fn new(path : String) -> PyResult<Corpus> {
+        let docs = sled::open(&path).map_err(|e| TeangaError::Serialization(format!("{}", e)))?;
+        Ok(Corpus {
             meta: HashMap::new(),
             order: Vec::new(),
+            docs,
             path
-        }
+        })
     }
 
     /// Add a layer to the corpus
@@ -56,12 +85,12 @@ impl Corpus {
     /// * `link_types` - The link types for this layer
     /// * `target` - The target layer for this layer
     /// * `default` - The default values for this layer
-    fn add_layer_meta(&mut self, name: String, type_: LayerType, 
-        on: String, data: Option<String>, link_types: Option<Vec<String>>, 
-        target: Option<String>, default: Option<Vec<String>>) -> PyResult<()> {
+    /// * `dim` - The vector dimensionality for this layer, if `type_` is `vector`
+    fn add_layer_meta(&mut self, name: String, type_: LayerType,
+        on: String, data: Option<String>, link_types: Option<Vec<String>>,
+        target: Option<String>, default: Option<Vec<String>>, dim: Option<usize>) -> PyResult<()> {
         if self.meta.contains_key(&name) {
-            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
-                format!("Layer {} already exists", name)))
+            return Err(TeangaError::LayerExists(name).into())
         }
         self.meta.insert(name.clone(), LayerDesc {
             name,
@@ -70,20 +99,293 @@ impl Corpus {
             data,
             link_types,
             target,
-            default
+            default,
+            dim
         });
         Ok(())
     }
+
+    /// Add a document to the corpus
+    /// # Arguments
+    /// * `content` - A mapping from layer name to the content for that layer
+    ///
+    /// # Returns
+    /// The content-hash ID under which the document was stored
+    fn add_doc(&mut self, mut content: HashMap<String, PyLayer>) -> PyResult<String> {
+        for name in content.keys() {
+            if !self.meta.contains_key(name) {
+                return Err(TeangaError::UnknownLayer(name.clone()).into())
+            }
+        }
+        for (name, layer) in content.iter_mut() {
+            if let PyLayer::VectorLayer(vectors) = layer {
+                let dim = self.meta.get(name).and_then(|desc| desc.dim);
+                for v in vectors.iter_mut() {
+                    if let Some(expected) = dim {
+                        if v.len() != expected {
+                            return Err(TeangaError::VectorDimMismatch { expected, got: v.len() }.into())
+                        }
+                    }
+                    normalize(v);
+                }
+            }
+        }
+        let id = teanga_id(&content);
+        let bytes = serde_json::to_vec(&content).map_err(|e| TeangaError::Serialization(format!("{}", e)))?;
+        self.docs.insert(id.as_bytes(), bytes).map_err(|e| TeangaError::Serialization(format!("{}", e)))?;
+        if !self.order.contains(&id) {
+            self.order.push(id.clone());
+        }
+        Ok(id)
+    }
+
+    /// Fetch a single document from the store by its content-hash ID
+    fn get_doc(&self, id: String) -> PyResult<HashMap<String, PyLayer>> {
+        get_doc_from_db(&self.docs, &id)
+    }
+
+    /// Fetch several documents from the store by their content-hash IDs
+    fn get_docs(&self, ids: Vec<String>) -> PyResult<Vec<HashMap<String, PyLayer>>> {
+        ids.iter().map(|id| get_doc_from_db(&self.docs, id)).collect()
+    }
+
+    /// Iterate over this corpus's documents in `order`, streaming each one
+    /// from the store rather than holding them all in memory at once
+    fn docs(&self) -> DocIterator {
+        DocIterator {
+            order: self.order.clone().into_iter(),
+            db: self.docs.clone()
+        }
+    }
+
+    /// Merge the documents and layer metadata of `other` into this corpus.
+    ///
+    /// Identically-named layers with matching descriptions are unified; a
+    /// same-named layer with an incompatible description is a typed error
+    /// rather than a silent overwrite. Documents are copied by their
+    /// content-hash ID, so duplicates across the two corpora collapse to one
+    /// entry, and `order` is extended only with genuinely new IDs.
+    fn merge(&mut self, other: PyRef<'_, Corpus>) -> PyResult<()> {
+        for (name, desc) in other.meta.iter() {
+            match self.meta.get(name) {
+                Some(existing) if existing.type_ == desc.type_
+                    && existing.on == desc.on
+                    && existing.target == desc.target
+                    && existing.link_types == desc.link_types
+                    && existing.dim == desc.dim => {}
+                Some(_) => return Err(TeangaError::LayerConflict(name.clone()).into()),
+                None => { self.meta.insert(name.clone(), desc.clone()); }
+            }
+        }
+        for id in &other.order {
+            let doc = get_doc_from_db(&other.docs, id)?;
+            let bytes = serde_json::to_vec(&doc).map_err(|e| TeangaError::Serialization(format!("{}", e)))?;
+            self.docs.insert(id.as_bytes(), bytes).map_err(|e| TeangaError::Serialization(format!("{}", e)))?;
+            if !self.order.contains(id) {
+                self.order.push(id.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the `k` elements of `layer` most similar to `query` by cosine
+    /// similarity, returning `(doc_id, element_index, score)` triples sorted
+    /// best-first.
+    ///
+    /// Vectors are stored already normalized to unit length (see
+    /// `add_doc`), and `query` is normalized once here, so cosine similarity
+    /// reduces to a single dot product per candidate. A size-`k` min-heap
+    /// keeps this a brute-force scan O(N log k) rather than O(N log N).
+    /// Stored vectors with zero norm (left un-normalized by `add_doc`) are
+    /// skipped rather than scored. `query` must match the layer's declared
+    /// `dim`, same as a vector inserted via `add_doc`; a shorter or longer
+    /// query would otherwise silently truncate in `dot`'s `zip` instead of
+    /// erroring.
+    fn nearest(&self, mut query: Vec<f32>, layer: String, k: usize) -> PyResult<Vec<(String, usize, f32)>> {
+        let desc = match self.meta.get(&layer) {
+            Some(desc) if desc.type_ == LayerType::vector => desc,
+            Some(_) => return Err(TeangaError::NotAVectorLayer(layer).into()),
+            None => return Err(TeangaError::UnknownLayer(layer).into())
+        };
+        if let Some(expected) = desc.dim {
+            if query.len() != expected {
+                return Err(TeangaError::VectorDimMismatch { expected, got: query.len() }.into())
+            }
+        }
+        normalize(&mut query);
+        let mut heap: BinaryHeap<Reverse<ScoredHit>> = BinaryHeap::new();
+        for id in &self.order {
+            let doc = get_doc_from_db(&self.docs, id)?;
+            if let Some(PyLayer::VectorLayer(vectors)) = doc.get(&layer) {
+                for (index, v) in vectors.iter().enumerate() {
+                    if v.iter().all(|x| *x == 0.0) {
+                        continue;
+                    }
+                    let score = dot(&query, v);
+                    if heap.len() < k {
+                        heap.push(Reverse(ScoredHit { score, doc_id: id.clone(), index }));
+                    } else if heap.peek().map_or(false, |Reverse(worst)| score > worst.score) {
+                        heap.pop();
+                        heap.push(Reverse(ScoredHit { score, doc_id: id.clone(), index }));
+                    }
+                }
+            }
+        }
+        let mut hits: Vec<ScoredHit> = heap.into_iter().map(|Reverse(hit)| hit).collect();
+        hits.sort_by(|a, b| b.cmp(a));
+        Ok(hits.into_iter().map(|hit| (hit.doc_id, hit.index, hit.score)).collect())
+    }
+
+    /// Serialize this corpus to a JSON string
+    fn to_json(&self) -> PyResult<String> {
+        serialization::write_corpus_to_json_string(self).map_err(|e|
+            TeangaError::Serialization(format!("{}", e)).into())
+    }
+
+    #[staticmethod]
+    /// Parse a corpus from a JSON string, storing its documents on disk at
+    /// `store_path`
+    fn from_json(json: String, store_path: String) -> PyResult<Corpus> {
+        serialization::read_corpus_from_json_string(&json, store_path).map_err(PyErr::from)
+    }
+
+    /// Serialize this corpus to a YAML string
+    fn to_yaml(&self) -> PyResult<String> {
+        serialization::write_corpus_to_yaml_string(self).map_err(|e|
+            TeangaError::Serialization(format!("{}", e)).into())
+    }
+
+    #[staticmethod]
+    /// Parse a corpus from a YAML string, storing its documents on disk at
+    /// `store_path`
+    fn from_yaml(yaml: String, store_path: String) -> PyResult<Corpus> {
+        serialization::read_corpus_from_yaml_string(&yaml, store_path).map_err(PyErr::from)
+    }
+
+    /// Save this corpus to a file in the compact binary format
+    fn save_binary(&self, path: String) -> PyResult<()> {
+        let bytes = serialization::write_corpus_to_bytes(self).map_err(|e| TeangaError::Serialization(format!("{}", e)))?;
+        std::fs::write(path, bytes).map_err(|e| TeangaError::Serialization(format!("{}", e)).into())
+    }
+
+    #[staticmethod]
+    /// Load a corpus from a file in the compact binary format.
+    ///
+    /// Deliberately takes `store_path` in addition to `path`, rather than
+    /// deriving one from the other: `path` is a flat CBOR archive file and
+    /// `store_path` is the directory for the `sled` document store, and
+    /// `sled::open` errors if given an existing regular file, so the two
+    /// can never be the same path (see `Corpus::new`/`save_binary`).
+    fn load_binary(path: String, store_path: String) -> PyResult<Corpus> {
+        let bytes = std::fs::read(&path).map_err(|e| TeangaError::Serialization(format!("{}", e)))?;
+        serialization::read_corpus_from_bytes(&bytes, store_path).map_err(PyErr::from)
+    }
+}
+
+/// Normalize a vector to unit length in place; a zero vector is left as-is
+fn normalize(v: &mut [f32]) {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        for x in v.iter_mut() {
+            *x /= norm;
+        }
+    }
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+/// A single `nearest` hit, ordered by score
+struct ScoredHit {
+    score: f32,
+    doc_id: String,
+    index: usize
+}
+
+impl PartialEq for ScoredHit {
+    fn eq(&self, other: &Self) -> bool {
+        self.score == other.score
+    }
+}
+
+impl Eq for ScoredHit {}
+
+impl PartialOrd for ScoredHit {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        self.score.partial_cmp(&other.score)
+    }
+}
+
+impl Ord for ScoredHit {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.partial_cmp(other).unwrap_or(std::cmp::Ordering::Equal)
+    }
+}
+
+/// Fetch and decode a single document from the content-addressable store
+pub(crate) fn get_doc_from_db(db: &sled::Db, id: &str) -> PyResult<HashMap<String, PyLayer>> {
+    let bytes = db.get(id.as_bytes()).map_err(|e| TeangaError::Serialization(format!("{}", e)))?
+        .ok_or_else(|| TeangaError::DocumentNotFound(id.to_string()))?;
+    serde_json::from_slice(&bytes).map_err(|e| TeangaError::Serialization(format!("{}", e)).into())
+}
+
+#[pyclass]
+/// Streams documents from a corpus's store in `order`, one at a time
+struct DocIterator {
+    order: std::vec::IntoIter<String>,
+    db: sled::Db
+}
+
+#[pymethods]
+impl DocIterator {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<'_, Self>) -> PyResult<Option<HashMap<String, PyLayer>>> {
+        match slf.order.next() {
+            Some(id) => Ok(Some(get_doc_from_db(&slf.db, &id)?)),
+            None => Ok(None)
+        }
+    }
+}
+
+const ID_LEN: usize = 4;
+
+/// Compute the content-hash ID for a document.
+///
+/// The hash covers every layer name and its serialized content, so two
+/// documents with identical content always receive the same ID regardless
+/// of insertion order. Teanga's own IDs are a short, URL-safe base64
+/// prefix of a content digest, not a full hex digest, so this takes the
+/// first `ID_LEN` base64 characters of the SHA-256 rather than the raw
+/// 64-character hex string.
+fn teanga_id(content: &HashMap<String, PyLayer>) -> String {
+    let mut keys: Vec<&String> = content.keys().collect();
+    keys.sort();
+    let mut hasher = Sha256::new();
+    for key in keys {
+        hasher.update(key.as_bytes());
+        hasher.update(b"\0");
+        if let Ok(bytes) = serde_json::to_vec(&content[key]) {
+            hasher.update(&bytes);
+        }
+        hasher.update(b"\0");
+    }
+    let digest = URL_SAFE_NO_PAD.encode(hasher.finalize());
+    digest[..ID_LEN].to_string()
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug,Clone)]
+#[derive(Debug,Clone,PartialEq,Serialize,Deserialize)]
 enum LayerType {
     characters,
     seq,
     div,
     element,
-    span
+    span,
+    vector
 }
 
 impl FromPyObject<'_> for LayerType {
@@ -94,8 +396,8 @@ impl FromPyObject<'_> for LayerType {
             "div" => Ok(LayerType::div),
             "element" => Ok(LayerType::element),
             "span" => Ok(LayerType::span),
-            _ => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
-                format!("Unknown layer type {}", ob.extract::<String>()?)))
+            "vector" => Ok(LayerType::vector),
+            other => Err(TeangaError::UnknownLayerType(other.to_string()).into())
         }
     }
 }
@@ -107,7 +409,50 @@ impl IntoPy<PyObject> for LayerType {
             LayerType::seq => "seq".into_py(py),
             LayerType::div => "div".into_py(py),
             LayerType::element => "element".into_py(py),
-            LayerType::span => "span".into_py(py)
+            LayerType::span => "span".into_py(py),
+            LayerType::vector => "vector".into_py(py)
+        }
+    }
+}
+
+/// The content of a single layer within a document.
+///
+/// A layer's shape depends on its `LayerType`: character layers hold a
+/// plain string, sequence-like layers hold a list of strings, span/element
+/// layers hold a list of index spans, and vector layers hold one
+/// fixed-length embedding per element of the layer they are `on`.
+#[derive(Debug,Clone,Serialize,Deserialize)]
+#[serde(untagged)]
+enum PyLayer {
+    CharacterLayer(String),
+    SeqLayer(Vec<String>),
+    IndexLayer(Vec<Vec<u32>>),
+    VectorLayer(Vec<Vec<f32>>)
+}
+
+impl FromPyObject<'_> for PyLayer {
+    fn extract(ob: &PyAny) -> PyResult<Self> {
+        if let Ok(s) = ob.extract::<String>() {
+            Ok(PyLayer::CharacterLayer(s))
+        } else if let Ok(v) = ob.extract::<Vec<String>>() {
+            Ok(PyLayer::SeqLayer(v))
+        } else if let Ok(v) = ob.extract::<Vec<Vec<u32>>>() {
+            Ok(PyLayer::IndexLayer(v))
+        } else if let Ok(v) = ob.extract::<Vec<Vec<f32>>>() {
+            Ok(PyLayer::VectorLayer(v))
+        } else {
+            Err(TeangaError::InvalidLayerValue("Could not convert value to a layer".to_string()).into())
+        }
+    }
+}
+
+impl IntoPy<PyObject> for PyLayer {
+    fn into_py(self, py: Python) -> PyObject {
+        match self {
+            PyLayer::CharacterLayer(s) => s.into_py(py),
+            PyLayer::SeqLayer(v) => v.into_py(py),
+            PyLayer::IndexLayer(v) => v.into_py(py),
+            PyLayer::VectorLayer(v) => v.into_py(py)
         }
     }
 }
@@ -115,7 +460,38 @@ impl IntoPy<PyObject> for LayerType {
 /// A Python module implemented in Rust.
 #[pymodule]
 #[pyo3(name="teangadb")]
-fn teangadb(_py: Python, m: &PyModule) -> PyResult<()> {
+fn teangadb(py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<Corpus>()?;
+    m.add_class::<DocIterator>()?;
+    errors::register(py, m)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `save_binary` writes an archive file; `load_binary` must be able to
+    /// re-open it with a document store at a *different* path, since the
+    /// archive is a plain file and the store is a sled directory.
+    #[test]
+    fn test_save_and_load_binary_use_distinct_paths() {
+        let store_path = std::env::temp_dir().join("teanga-lib-test-store").to_string_lossy().to_string();
+        let archive_path = std::env::temp_dir().join("teanga-lib-test-archive.cbor").to_string_lossy().to_string();
+        let _ = std::fs::remove_dir_all(&store_path);
+        let _ = std::fs::remove_file(&archive_path);
+
+        let mut corpus = Corpus::new(store_path).unwrap();
+        corpus.add_layer_meta("text".to_string(), LayerType::characters,
+            "".to_string(), None, None, None, None, None).unwrap();
+        corpus.add_doc(HashMap::from([
+            ("text".to_string(), PyLayer::CharacterLayer("hello".to_string()))
+        ])).unwrap();
+        corpus.save_binary(archive_path.clone()).unwrap();
+
+        let reload_store_path = std::env::temp_dir().join("teanga-lib-test-store-reload").to_string_lossy().to_string();
+        let _ = std::fs::remove_dir_all(&reload_store_path);
+        let reread = Corpus::load_binary(archive_path, reload_store_path).unwrap();
+        assert_eq!(reread.order, corpus.order);
+    }
+}
@@ -1,12 +1,22 @@
 // Serialization support for Teanga DB
 // -----------------------------------------------------------------------------
 use serde::de::Visitor;
-use crate::{Corpus, LayerDesc, PyLayer};
+use serde::ser::{Serialize, Serializer, SerializeMap};
+use crate::{Corpus, LayerDesc, PyLayer, teanga_id, get_doc_from_db};
+use crate::errors::TeangaError;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
 use serde::Deserializer;
 use std::cmp::min;
 
-struct TeangaVisitor(String);
+/// A `Visitor` can only report failure through `A::Error`, which flattens
+/// any concrete error to a string (`serde::de::Error::custom`). To let a
+/// hash mismatch survive as a real `TeangaError::DocumentHashMismatch` (and
+/// so map to `DocumentHashError` in Python, not the generic base exception),
+/// the visitor stashes the typed error here before raising the generic one;
+/// `deserialize_corpus` recovers it afterwards.
+struct TeangaVisitor(String, Rc<RefCell<Option<TeangaError>>>);
 
 impl<'de> Visitor<'de> for TeangaVisitor {
     type Value = Corpus;
@@ -21,20 +31,22 @@ impl<'de> Visitor<'de> for TeangaVisitor {
         let mut corpus = Corpus::new(self.0).map_err(serde::de::Error::custom)?;
         while let Some(ref key) = map.next_key::<String>()? {
             if key == "_meta" {
-                eprintln!("Reading meta");
-                let data = map.next_value::<HashMap<String, LayerDesc>>()?;
+                let mut data = map.next_value::<HashMap<String, LayerDesc>>()?;
+                for (name, desc) in data.iter_mut() {
+                    desc.name = name.clone();
+                }
                 corpus.meta = data;
-                eprintln!("Meta: {:?}", corpus.meta);
             } else if key == "_order" {
-                eprintln!("Reading order");
                 let data = map.next_value::<Vec<String>>()?;
                 corpus.order = data;
             } else {
-                eprintln!("Reading doc {}", key);
                 let doc = map.next_value::<HashMap<String, PyLayer>>()?;
                 let id = corpus.add_doc(doc).map_err(serde::de::Error::custom)?;
                 if id[..min(id.len(), key.len())] != key[..min(id.len(), key.len())] {
-                    return Err(serde::de::Error::custom(format!("Document fails hash check: {} != {}", id, key)))
+                    let err = TeangaError::DocumentHashMismatch { expected: id, got: key.clone() };
+                    let msg = err.to_string();
+                    *self.1.borrow_mut() = Some(err);
+                    return Err(serde::de::Error::custom(msg))
                 }
             }
         }
@@ -42,16 +54,75 @@ impl<'de> Visitor<'de> for TeangaVisitor {
     }
 }
 
-fn read_corpus_from_json_string(s: &str, path : String) -> Result<Corpus, serde_json::Error> {
-    let mut deserializer = serde_json::Deserializer::from_str(s);
-    deserializer.deserialize_any(TeangaVisitor(path))
+/// Run a deserializer with a `TeangaVisitor`, recovering the typed error it
+/// may have stashed rather than flattening every failure to
+/// `TeangaError::Serialization`.
+fn deserialize_corpus<E>(path: String, deserialize: impl FnOnce(TeangaVisitor) -> Result<Corpus, E>) -> Result<Corpus, TeangaError>
+    where E: std::fmt::Display
+{
+    let slot = Rc::new(RefCell::new(None));
+    let visitor = TeangaVisitor(path, slot.clone());
+    deserialize(visitor).map_err(|e|
+        slot.borrow_mut().take().unwrap_or_else(|| TeangaError::Serialization(format!("{}", e))))
+}
+
+/// `Serialize` mirrors `TeangaVisitor`: a `_meta` entry, a `_order` entry,
+/// then one entry per document keyed by its content hash. The key is
+/// recomputed from the document content at write time (rather than trusting
+/// `order`) so that anything we emit is guaranteed to pass the hash check
+/// on the way back in.
+impl Serialize for Corpus {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        let mut map = serializer.serialize_map(Some(self.order.len() + 2))?;
+        map.serialize_entry("_meta", &self.meta)?;
+        map.serialize_entry("_order", &self.order)?;
+        for id in &self.order {
+            let doc = get_doc_from_db(&self.docs, id).map_err(serde::ser::Error::custom)?;
+            map.serialize_entry(&teanga_id(&doc), &doc)?;
+        }
+        map.end()
+    }
+}
+
+pub(crate) fn read_corpus_from_json_string(s: &str, path : String) -> Result<Corpus, TeangaError> {
+    deserialize_corpus(path, |visitor| {
+        let mut deserializer = serde_json::Deserializer::from_str(s);
+        deserializer.deserialize_any(visitor)
+    })
+}
+
+pub(crate) fn read_corpus_from_yaml_string(s: &str, path : String) -> Result<Corpus, TeangaError> {
+    deserialize_corpus(path, |visitor| {
+        let deserializer = serde_yaml::Deserializer::from_str(s);
+        deserializer.deserialize_any(visitor)
+    })
 }
 
-fn read_corpus_from_yaml_string(s: &str, path : String) -> Result<Corpus, serde_yaml::Error> {
-    let deserializer = serde_yaml::Deserializer::from_str(s);
-    deserializer.deserialize_any(TeangaVisitor(path))
+pub(crate) fn write_corpus_to_json_string(corpus: &Corpus) -> Result<String, serde_json::Error> {
+    serde_json::to_string(corpus)
 }
 
+pub(crate) fn write_corpus_to_yaml_string(corpus: &Corpus) -> Result<String, serde_yaml::Error> {
+    serde_yaml::to_string(corpus)
+}
+
+/// Compact binary format (CBOR) for large corpora, where `serde_json`/
+/// `serde_yaml`'s text parsing is too slow and bulky. Reuses the same
+/// `TeangaVisitor`/`Serialize for Corpus` wiring as the text formats, so
+/// `_meta`/`_order`/documents are encoded and decoded document-by-document
+/// rather than materializing an intermediate tree.
+pub(crate) fn read_corpus_from_bytes(bytes: &[u8], path : String) -> Result<Corpus, TeangaError> {
+    deserialize_corpus(path, |visitor| {
+        let mut deserializer = serde_cbor::Deserializer::from_slice(bytes);
+        deserializer.deserialize_any(visitor)
+    })
+}
+
+pub(crate) fn write_corpus_to_bytes(corpus: &Corpus) -> Result<Vec<u8>, serde_cbor::Error> {
+    serde_cbor::to_vec(corpus)
+}
 
 #[cfg(test)]
 mod tests {
@@ -59,18 +130,72 @@ mod tests {
 
     #[test]
     fn test_deserialize_yaml() {
+        let doc = example_doc_yaml();
+        read_corpus_from_yaml_string(&doc, test_db_path("test_deserialize_yaml")).unwrap();
+    }
+
+    #[test]
+    fn test_hash_mismatch_is_a_document_hash_error() {
         let doc = "_meta:
     text:
         type: characters
+_order: [\"not-the-hash\"]
+not-the-hash:
+    text: This is an example
+";
+        let err = read_corpus_from_yaml_string(doc, test_db_path("test_hash_mismatch")).unwrap_err();
+        match err {
+            TeangaError::DocumentHashMismatch { expected, got } => {
+                assert_eq!(got, "not-the-hash");
+                assert_ne!(expected, got);
+            }
+            other => panic!("expected DocumentHashMismatch, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_json() {
+        let doc = example_doc_yaml();
+        let corpus = read_corpus_from_yaml_string(&doc, test_db_path("test_roundtrip_json_a")).unwrap();
+        let json = write_corpus_to_json_string(&corpus).unwrap();
+        let reread = read_corpus_from_json_string(&json, test_db_path("test_roundtrip_json_b")).unwrap();
+        assert_eq!(reread.order, corpus.order);
+    }
+
+    #[test]
+    fn test_roundtrip_bytes() {
+        let doc = example_doc_yaml();
+        let corpus = read_corpus_from_yaml_string(&doc, test_db_path("test_roundtrip_bytes_a")).unwrap();
+        let bytes = write_corpus_to_bytes(&corpus).unwrap();
+        let reread = read_corpus_from_bytes(&bytes, test_db_path("test_roundtrip_bytes_b")).unwrap();
+        assert_eq!(reread.order, corpus.order);
+    }
+
+    /// A scratch sled store path unique to this test, under the OS temp dir
+    fn test_db_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("teanga-{}", name)).to_string_lossy().to_string()
+    }
+
+    /// A one-document corpus fixture, keyed by its actual content-hash ID
+    /// (computed via `teanga_id` rather than hardcoded) so the fixture stays
+    /// valid regardless of `teanga_id`'s output format.
+    fn example_doc_yaml() -> String {
+        let mut content = HashMap::new();
+        content.insert("text".to_string(), PyLayer::CharacterLayer("This is an example".to_string()));
+        content.insert("tokens".to_string(), PyLayer::IndexLayer(vec![
+            vec![0, 4], vec![5, 7], vec![8, 10], vec![11, 18]
+        ]));
+        let id = teanga_id(&content);
+        format!("_meta:
+    text:
+        type: characters
     tokens:
         type: span
         on: text
-_order: [\"ecWc\"]
-ecWc:
+_order: [\"{id}\"]
+{id}:
     text: This is an example
     tokens: [[0, 4], [5, 7], [8, 10], [11, 18]]
-";
-        read_corpus_from_yaml_string(doc, "tmp".to_string()).unwrap();
+")
     }
 }
-